@@ -1,12 +1,24 @@
 use std::collections::{BTreeMap, HashSet, VecDeque};
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::{env, io::Error};
 
+use ciborium::ser::into_writer;
+use cxx::let_cxx_string;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
 use log::info;
+use rustls_pemfile::{certs, pkcs8_private_keys};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{interval, Duration};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::Message;
 
 #[derive(Serialize, Deserialize)]
 struct NodeRepr {
@@ -49,22 +61,79 @@ mod ffi {
 
         fn new_runtime_instance(sample_rate: f64, block_size: usize) -> UniquePtr<RuntimeBindings>;
         fn apply_instructions(self: Pin<&mut RuntimeBindings>, batch: &String) -> i32;
+
+        unsafe fn apply_instructions_cbor(
+            self: Pin<&mut RuntimeBindings>,
+            batch: *const u8,
+            length: usize,
+        ) -> i32;
+
+        unsafe fn update_shared_resource_map(
+            self: Pin<&mut RuntimeBindings>,
+            name: &CxxString,
+            data: *const f32,
+            size: usize,
+        ) -> bool;
+
+        // Returns a JSON array of `{source, event, value}` records queued by
+        // meter/scope/snapshot nodes since the last drain, or "[]" if none.
+        fn drain_events(self: Pin<&mut RuntimeBindings>) -> String;
     }
 }
 
+// The single owner of the C++ runtime and its node map. Lives inside one
+// dedicated task (see `spawn_runtime_actor`) so only that task ever touches
+// the `UniquePtr`, and so every connected client reconciles into the same
+// live graph rather than each getting a private runtime.
 pub struct RuntimeWrapper {
-    runtime: Arc<Mutex<cxx::UniquePtr<ffi::RuntimeBindings>>>,
+    runtime: cxx::UniquePtr<ffi::RuntimeBindings>,
     node_map: BTreeMap<i32, ShallowNodeRepr>,
+    // Debugging escape hatch: hand instructions to the runtime as a JSON
+    // string instead of CBOR bytes.
+    json_debug: bool,
 }
 
 impl RuntimeWrapper {
-    pub fn new() -> Self {
+    pub fn new(json_debug: bool) -> Self {
         Self {
-            runtime: Arc::new(Mutex::new(ffi::new_runtime_instance(44100.0, 512))),
+            runtime: ffi::new_runtime_instance(44100.0, 512),
             node_map: BTreeMap::new(),
+            json_debug,
+        }
+    }
+
+    pub fn update_shared_resource(&mut self, name: &str, data: &[f32]) -> bool {
+        let_cxx_string!(cxx_name = name);
+
+        unsafe {
+            self.runtime
+                .pin_mut()
+                .update_shared_resource_map(&cxx_name, data.as_ptr(), data.len())
         }
     }
 
+    pub fn apply_instructions(&mut self, instructions: &serde_json::Value) -> i32 {
+        if self.json_debug {
+            return self
+                .runtime
+                .pin_mut()
+                .apply_instructions(&instructions.to_string());
+        }
+
+        let mut batch = Vec::new();
+        into_writer(instructions, &mut batch).expect("Failed to CBOR-encode instructions");
+
+        unsafe {
+            self.runtime
+                .pin_mut()
+                .apply_instructions_cbor(batch.as_ptr(), batch.len())
+        }
+    }
+
+    pub fn drain_events(&mut self) -> String {
+        self.runtime.pin_mut().drain_events()
+    }
+
     pub fn reconcile(&mut self, roots: &Vec<NodeRepr>) -> serde_json::Value {
         let mut visited: HashSet<i32> = HashSet::new();
         let mut queue: VecDeque<&NodeRepr> = VecDeque::new();
@@ -82,6 +151,8 @@ impl RuntimeWrapper {
                 continue;
             }
 
+            let incoming_children = next.children.iter().map(|n| n.hash).collect::<Vec<i32>>();
+
             // Mount
             if !self.node_map.contains_key(&next.hash) {
                 // Create node
@@ -100,18 +171,54 @@ impl RuntimeWrapper {
                     ]));
                 }
 
-                self.node_map.insert(next.hash, shallow_clone(&next));
+                // Insert with empty props/children so the shared diff loop
+                // below treats every incoming prop as changed and emits a
+                // [3, hash, name, value] instruction for it on first mount.
+                let mut mounted = shallow_clone(&next);
+                mounted.props.clear();
+                mounted.children.clear();
+                self.node_map.insert(next.hash, mounted);
+            } else {
+                // Already mounted: only append edges that weren't already
+                // present last render, rather than re-appending the whole
+                // list (APPEND_CHILD is additive, so re-sending an unchanged
+                // edge would duplicate it in the C++ node's child list).
+                let existing = self.node_map.get(&next.hash).unwrap();
+
+                for child in next.children.iter() {
+                    if !existing.children.contains(&child.hash) {
+                        instructions.as_array_mut().unwrap().push(json!([
+                            2,
+                            next.hash,
+                            child.hash,
+                            child.output_channel
+                        ]));
+                    }
+                }
             }
 
-            // Props
+            // Props are append-only: we only emit an instruction when an
+            // incoming value actually changed from what the runtime last
+            // received. There's no "unset" instruction in the wire protocol
+            // (opcode 3 always carries a value), so a prop that disappears
+            // from a node's props this render is left at its last value in
+            // the runtime rather than reset; clearing it takes sending the
+            // node's default value explicitly, the same as any other change.
+            let existing = self.node_map.get_mut(&next.hash).unwrap();
+
             for (name, value) in &next.props {
-                // TODO: Only add the instruction if the prop value != existing prop value
-                instructions
-                    .as_array_mut()
-                    .unwrap()
-                    .push(json!([3, next.hash, name, value]));
+                if existing.props.get(name) != Some(value) {
+                    instructions
+                        .as_array_mut()
+                        .unwrap()
+                        .push(json!([3, next.hash, name, value]));
+
+                    existing.props.insert(name.clone(), value.clone());
+                }
             }
 
+            existing.children = incoming_children;
+
             for child in next.children.iter() {
                 queue.push_back(child);
             }
@@ -119,6 +226,21 @@ impl RuntimeWrapper {
             visited.insert(next.hash);
         }
 
+        // Sweep: destroy any previously mounted node that's no longer
+        // reachable from the new roots, so the C++ shared node map doesn't
+        // grow unbounded across renders.
+        let stale: Vec<i32> = self
+            .node_map
+            .keys()
+            .filter(|hash| !visited.contains(hash))
+            .copied()
+            .collect();
+
+        for hash in stale.iter() {
+            instructions.as_array_mut().unwrap().push(json!([1, hash]));
+            self.node_map.remove(hash);
+        }
+
         // Activate roots
         instructions.as_array_mut().unwrap().push(json!([
             4,
@@ -138,41 +260,256 @@ impl RuntimeWrapper {
     }
 }
 
-// Basically the cxx::UniquePtr type wraps a C-style opaque pointer and
-// because of that cannot guarantee the ability to move the UniquePtr across
-// threads, which we may need here in Tokio land because we're not sure which
-// thread pool thread we'll be on when we receive the next websocket message.
-//
-// To get around that, I've made this wrapper class with access secured behind
-// a mutex, which truthfully I think is probably unnecessary but that gave me
-// the opportunity to add this unsafe impl Send which convinces the compiler that
-// we'll be ok. I think there's probably a cleaner way, but this is good enough for
-// now, I want to get to the fun stuff.
+// The cxx::UniquePtr type wraps a C-style opaque pointer and because of that
+// cannot guarantee the ability to move across threads. The runtime actor task
+// owns the only RuntimeWrapper and never shares it, but the task itself can be
+// scheduled onto any pool thread between `.await` points, so the future still
+// needs to be Send.
 unsafe impl Send for RuntimeWrapper {}
 
+#[cfg(test)]
+mod reconcile_tests {
+    use super::*;
+
+    fn node(hash: i32, kind: &str, props: &[(&str, i64)], children: Vec<NodeRepr>) -> NodeRepr {
+        let mut map = serde_json::Map::new();
+        for (name, value) in props {
+            map.insert(name.to_string(), json!(value));
+        }
+
+        NodeRepr {
+            hash,
+            kind: kind.to_string(),
+            props: map,
+            output_channel: 0,
+            children,
+        }
+    }
+
+    fn opcodes(instructions: &serde_json::Value, opcode: i64) -> Vec<&serde_json::Value> {
+        instructions
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|i| i[0].as_i64() == Some(opcode))
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_second_render_emits_no_appends_or_props() {
+        let mut runtime = RuntimeWrapper::new(true);
+        let child = node(2, "const", &[("value", 1)], vec![]);
+        let roots = vec![node(1, "add", &[], vec![child])];
+
+        runtime.reconcile(&roots);
+        let instructions = runtime.reconcile(&roots);
+
+        assert!(opcodes(&instructions, 0).is_empty());
+        assert!(opcodes(&instructions, 2).is_empty());
+        assert!(opcodes(&instructions, 3).is_empty());
+        assert!(opcodes(&instructions, 1).is_empty());
+    }
+
+    #[test]
+    fn changed_prop_emits_exactly_one_instruction() {
+        let mut runtime = RuntimeWrapper::new(true);
+        let roots = vec![node(1, "const", &[("value", 1)], vec![])];
+        runtime.reconcile(&roots);
+
+        let roots = vec![node(1, "const", &[("value", 2)], vec![])];
+        let instructions = runtime.reconcile(&roots);
+
+        let prop_instructions = opcodes(&instructions, 3);
+        assert_eq!(prop_instructions.len(), 1);
+        assert_eq!(prop_instructions[0], &json!([3, 1, "value", 2]));
+    }
+
+    #[test]
+    fn added_child_only_appends_the_new_edge() {
+        let mut runtime = RuntimeWrapper::new(true);
+        let a = node(2, "const", &[], vec![]);
+        let roots = vec![node(1, "add", &[], vec![a])];
+        runtime.reconcile(&roots);
+
+        let a = node(2, "const", &[], vec![]);
+        let b = node(3, "const", &[], vec![]);
+        let roots = vec![node(1, "add", &[], vec![a, b])];
+        let instructions = runtime.reconcile(&roots);
+
+        let appends = opcodes(&instructions, 2);
+        assert_eq!(appends.len(), 1);
+        assert_eq!(appends[0], &json!([2, 1, 3, 0]));
+    }
+
+    #[test]
+    fn dropped_node_emits_destroy_and_is_removed_from_node_map() {
+        let mut runtime = RuntimeWrapper::new(true);
+        let child = node(2, "const", &[], vec![]);
+        let roots = vec![node(1, "add", &[], vec![child])];
+        runtime.reconcile(&roots);
+
+        let roots = vec![node(1, "add", &[], vec![])];
+        let instructions = runtime.reconcile(&roots);
+
+        assert_eq!(opcodes(&instructions, 1), vec![&json!([1, 2])]);
+        assert!(!runtime.node_map.contains_key(&2));
+    }
+}
+
+enum Command {
+    Reconcile {
+        graph: Vec<NodeRepr>,
+        reply: oneshot::Sender<i32>,
+    },
+    UpdateResource {
+        name: String,
+        data: Vec<f32>,
+        reply: oneshot::Sender<bool>,
+    },
+}
+
+// Spawns the task that owns the single, shared RuntimeWrapper and processes
+// commands sent to it from any number of WebSocket connections, one at a
+// time. Returns a Sender that each connection clones to submit work, and a
+// broadcast Sender that every connection subscribes to in order to receive
+// runtime events (meters, scopes, snapshots) as they're drained.
+fn spawn_runtime_actor(json_debug: bool) -> (mpsc::Sender<Command>, broadcast::Sender<String>) {
+    let (tx, mut rx) = mpsc::channel::<Command>(32);
+    let (events_tx, _) = broadcast::channel::<String>(32);
+    let events_tx_actor = events_tx.clone();
+
+    tokio::spawn(async move {
+        let mut runtime = RuntimeWrapper::new(json_debug);
+        let mut events_timer = interval(Duration::from_millis(16));
+
+        loop {
+            tokio::select! {
+                command = rx.recv() => {
+                    match command {
+                        Some(Command::Reconcile { graph, reply }) => {
+                            let instructions = runtime.reconcile(&graph);
+                            let result = runtime.apply_instructions(&instructions);
+                            let _ = reply.send(result);
+                        }
+                        Some(Command::UpdateResource { name, data, reply }) => {
+                            let stored = runtime.update_shared_resource(&name, &data);
+                            let _ = reply.send(stored);
+                        }
+                        None => break,
+                    }
+                }
+                _ = events_timer.tick() => {
+                    let events = runtime.drain_events();
+
+                    // An Err here just means no client is currently
+                    // subscribed to receive them.
+                    if events != "[]" {
+                        let _ = events_tx_actor.send(events);
+                    }
+                }
+            }
+        }
+    });
+
+    (tx, events_tx)
+}
+
+// Loads a PEM certificate chain and private key from disk and builds the
+// rustls server config used to terminate `wss://` connections. Only PKCS#8
+// private keys are supported for now, matching what our docs tell users to
+// generate.
+fn load_tls_config(cert_path: &str, key_path: &str) -> Arc<ServerConfig> {
+    let cert_file = &mut BufReader::new(File::open(cert_path).expect("Failed to open TLS cert"));
+    let key_file = &mut BufReader::new(File::open(key_path).expect("Failed to open TLS key"));
+
+    let cert_chain = certs(cert_file)
+        .expect("Failed to parse TLS cert")
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut keys = pkcs8_private_keys(key_file).expect("Failed to parse TLS key");
+
+    if keys.is_empty() {
+        panic!("No PKCS#8 private keys found in {}", key_path);
+    }
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, PrivateKey(keys.remove(0)))
+        .expect("Failed to build TLS server config");
+
+    Arc::new(config)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let _ = env_logger::try_init();
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let cert_path = args.next();
+    let key_path = args.next();
+
+    // TLS is opt-in: only switch on `wss://` when both a cert and a key path
+    // are given on the command line.
+    let acceptor = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            Some(TlsAcceptor::from(load_tls_config(&cert_path, &key_path)))
+        }
+        _ => None,
+    };
+
+    // Opt into the slower JSON instruction path (instead of CBOR) for
+    // debugging by setting ELEMENTARY_JSON_DEBUG to any value.
+    let json_debug = env::var("ELEMENTARY_JSON_DEBUG").is_ok();
+
+    // One runtime, shared by every connection, driven by a single actor task.
+    let (commands, events) = spawn_runtime_actor(json_debug);
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
-    info!("Listening on: {}", addr);
-
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(accept_connection(stream));
+    info!(
+        "Listening on: {} ({})",
+        addr,
+        if acceptor.is_some() { "wss" } else { "ws" }
+    );
+
+    while let Ok((stream, peer_addr)) = listener.accept().await {
+        let commands = commands.clone();
+        let events = events.clone();
+
+        match acceptor.clone() {
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(tls_stream) => {
+                            accept_connection(tls_stream, peer_addr, commands, events).await
+                        }
+                        Err(e) => {
+                            println!("TLS handshake failed for {}: {}", peer_addr, e);
+                        }
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(accept_connection(stream, peer_addr, commands, events));
+            }
+        }
     }
 
     Ok(())
 }
 
-async fn accept_connection(stream: TcpStream) {
-    let addr = stream
-        .peer_addr()
-        .expect("connected streams should have a peer address");
+async fn accept_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<String>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     info!("Peer address: {}", addr);
 
     let ws_stream = tokio_tungstenite::accept_async(stream)
@@ -181,36 +518,88 @@ async fn accept_connection(stream: TcpStream) {
 
     info!("New WebSocket connection: {}", addr);
 
-    let mut runtime = RuntimeWrapper::new();
     let (mut write, mut read) = ws_stream.split();
-
-    while let Ok(next) = read.try_next().await {
-        if let Some(msg) = next {
-            match msg.to_text() {
-                Ok(text) => {
-                    println!("Received a message from {}: {}", addr, text);
-                    let directive: Directive =
-                        serde_json::from_str(text).unwrap_or(Directive { graph: None });
-
-                    if let Some(graph) = directive.graph {
-                        let instructions = runtime.reconcile(&graph);
-                        let result = runtime
-                            .runtime
-                            .lock()
-                            .unwrap()
-                            .as_mut()
-                            .unwrap()
-                            .apply_instructions(&instructions.to_string());
-
-                        println!("Apply instructions result: {}", result);
+    let mut events = events.subscribe();
+
+    loop {
+        tokio::select! {
+            next = read.try_next() => {
+                let Ok(Some(msg)) = next else { break };
+
+                match &msg {
+                    Message::Binary(data) => {
+                        match parse_resource_buffer(data) {
+                            Some((name, samples)) => {
+                                let (reply_tx, reply_rx) = oneshot::channel();
+                                let sent = commands
+                                    .send(Command::UpdateResource {
+                                        name: name.to_string(),
+                                        data: samples,
+                                        reply: reply_tx,
+                                    })
+                                    .await;
+
+                                let stored = match sent {
+                                    Ok(()) => reply_rx.await.unwrap_or(false),
+                                    Err(_) => false,
+                                };
+
+                                println!(
+                                    "Updated shared resource '{}' from {}: {}",
+                                    name, addr, stored
+                                );
+                            }
+                            None => {
+                                println!("Received a malformed resource buffer from {}", addr);
+                            }
+                        }
+
+                        // TODO: Properly handle the write failure case
+                        write.send(msg).await.unwrap()
                     }
-
-                    // TODO: Properly handle the write failure case
-                    write.send(msg).await.unwrap()
+                    _ => match msg.to_text() {
+                        Ok(text) => {
+                            println!("Received a message from {}: {}", addr, text);
+                            let directive: Directive =
+                                serde_json::from_str(text).unwrap_or(Directive { graph: None });
+
+                            if let Some(graph) = directive.graph {
+                                let (reply_tx, reply_rx) = oneshot::channel();
+                                let sent = commands
+                                    .send(Command::Reconcile {
+                                        graph,
+                                        reply: reply_tx,
+                                    })
+                                    .await;
+
+                                let result = match sent {
+                                    Ok(()) => reply_rx.await.unwrap_or(-1),
+                                    Err(_) => -1,
+                                };
+
+                                println!("Apply instructions result: {}", result);
+                            }
+
+                            // TODO: Properly handle the write failure case
+                            write.send(msg).await.unwrap()
+                        }
+                        Err(e) => {
+                            println!("Received a non-text message from {}: {}", addr, e);
+                            write.send("No thanks".into()).await.unwrap()
+                        }
+                    },
                 }
-                Err(e) => {
-                    println!("Received a non-text message from {}: {}", addr, e);
-                    write.send("No thanks".into()).await.unwrap()
+            }
+            batch = events.recv() => {
+                match batch {
+                    Ok(batch) => {
+                        // TODO: Properly handle the write failure case
+                        write.send(Message::Text(batch)).await.unwrap();
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        println!("Dropped {} event batches for {} (receiver lagged)", skipped, addr);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
         }
@@ -218,3 +607,88 @@ async fn accept_connection(stream: TcpStream) {
 
     println!("Connection closed to peer {}", addr);
 }
+
+// Parses a resource buffer frame: a little-endian u32 giving the length of a
+// UTF-8 resource name, the name itself, then the remaining bytes as
+// little-endian f32 samples.
+fn parse_resource_buffer(data: &[u8]) -> Option<(&str, Vec<f32>)> {
+    if data.len() < 4 {
+        return None;
+    }
+
+    let name_len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    let name_start = 4;
+    let name_end = name_start.checked_add(name_len)?;
+
+    let name = std::str::from_utf8(data.get(name_start..name_end)?).ok()?;
+    let samples_bytes = data.get(name_end..)?;
+
+    if samples_bytes.len() % 4 != 0 {
+        return None;
+    }
+
+    let samples = samples_bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+
+    Some((name, samples))
+}
+
+#[cfg(test)]
+mod parse_resource_buffer_tests {
+    use super::*;
+
+    fn frame(name: &str, samples: &[f32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+
+        for sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn round_trips_a_valid_frame() {
+        let data = frame("test", &[0.0, 1.0, -0.5]);
+
+        let (name, samples) = parse_resource_buffer(&data).unwrap();
+
+        assert_eq!(name, "test");
+        assert_eq!(samples, vec![0.0, 1.0, -0.5]);
+    }
+
+    #[test]
+    fn rejects_buffers_shorter_than_the_length_prefix() {
+        assert_eq!(parse_resource_buffer(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn rejects_name_len_overrunning_the_buffer() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&100u32.to_le_bytes());
+        data.extend_from_slice(b"short");
+
+        assert_eq!(parse_resource_buffer(&data), None);
+    }
+
+    #[test]
+    fn rejects_non_utf8_names() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[0xff, 0xfe]);
+
+        assert_eq!(parse_resource_buffer(&data), None);
+    }
+
+    #[test]
+    fn rejects_sample_bytes_not_a_multiple_of_four() {
+        let mut data = frame("t", &[1.0]);
+        data.pop();
+
+        assert_eq!(parse_resource_buffer(&data), None);
+    }
+}